@@ -0,0 +1,63 @@
+// Optional payload-obfuscation layer for the per-player WebSocket. Opt-in
+// via main's ENCRYPT flag so plaintext JSON still works for browser
+// debugging; when enabled, every Response/Request frame after the
+// handshake is a ChaCha20-Poly1305 ciphertext carried as a binary ws frame.
+//
+// This is NOT confidentiality against anyone who can observe the wire: the
+// session key crosses the same connection, in the clear, as the very first
+// frame (see `generate_session_key`), so a passive eavesdropper on the
+// connection recovers the key before any ciphertext follows. What it does
+// buy is that the payload is no longer plain JSON to anything that only
+// sees *some* of the traffic or isn't specifically watching for the key
+// frame — e.g. a proxy/log that captures message bodies but not connection
+// setup, or casual inspection of a captured frame out of context.
+
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, NewAead};
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+pub const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+pub type SessionKey = [u8; KEY_LEN];
+
+// One session key per connection, generated by the server and handed to the
+// client in the clear as the very first ws frame (the "handshake"). There's
+// no asymmetric exchange, so anyone who can observe that frame recovers the
+// key outright; see the module-level note on what protection this does and
+// doesn't provide.
+pub fn generate_session_key() -> SessionKey {
+    let mut key = [0u8; KEY_LEN];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+// Prepends a fresh random nonce to the AEAD ciphertext so the receiver
+// never has to track per-connection message counters.
+pub fn encrypt(key: &SessionKey, plaintext: &[u8]) -> Vec<u8> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce_bytes);
+    let nonce = Nonce::from_slice(&nonce_bytes);
+
+    let ciphertext = cipher.encrypt(nonce, plaintext).expect("chacha20poly1305 encryption failed");
+
+    let mut framed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    framed.extend_from_slice(&nonce_bytes);
+    framed.extend_from_slice(&ciphertext);
+    framed
+}
+
+// Returns None on a short frame or a failed tag check; callers should treat
+// that the same as any other malformed message.
+pub fn decrypt(key: &SessionKey, framed: &[u8]) -> Option<Vec<u8>> {
+    if framed.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce_bytes, ciphertext) = framed.split_at(NONCE_LEN);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce = Nonce::from_slice(nonce_bytes);
+    cipher.decrypt(nonce, ciphertext).ok()
+}
@@ -1,13 +1,26 @@
-use rand::{thread_rng, Rng};
-use crossbeam_channel::{Sender, Receiver};
+use rand::{thread_rng, Rng, SeedableRng};
+use rand::rngs::StdRng;
+use crossbeam_channel::{select, tick, Sender, Receiver};
 use time::precise_time_s;
-use std::collections::HashMap;
+use std::collections::{HashMap, BinaryHeap};
+use std::cmp::Reverse;
+use std::time::Duration;
 use serde_derive::{Serialize, Deserialize};
+use thiserror::Error;
 use log::{debug};
 
+const TICK: Duration = Duration::from_millis(50);
+
+// Minimum spacing enforced between generated nodes (see gen_nodes) and,
+// not coincidentally, the cell side length used by SpatialGrid: a cell
+// is no wider than the spacing radius, so a 1-ring scan always finds every
+// neighbor within that radius.
+const MIN_SPACING: f32 = 100.;
+
 pub type PlayerId = usize;
 pub type NodeId = usize;
 pub type LinkId = usize;
+pub type RoomId = usize;
 
 #[derive(Clone, Debug)]
 #[derive(Serialize)]
@@ -17,9 +30,71 @@ pub enum Response {
     FlidState{flids: Vec<Flid>},
     FlidUpdate{flid: Flid},
     Hello{id: PlayerId},
+    RoomList{rooms: Vec<RoomInfo>},
+    Route{link_ids: Vec<LinkId>},
+    ServerStatus{status: ServerStatus},
+    Error{code: String, message: String},
     Nop,
 }
 
+#[derive(Clone, Debug)]
+#[derive(Serialize)]
+#[serde(tag = "status")]
+pub enum ServerStatus {
+    Ok {
+        player_count: usize,
+        node_count: usize,
+        link_count: usize,
+        seed: u64,
+        uptime: f64,
+    },
+    Error{message: String},
+}
+
+// Rejections surfaced to the offending player as Response::Error; `code`
+// gives the client a stable machine-readable tag while the thiserror
+// message stays free to read for logs.
+#[derive(Copy, Clone, Debug, Error)]
+pub enum GameError {
+    #[error("no link with id {0}")]
+    UnknownLink(LinkId),
+    #[error("already jumping between nodes")]
+    AlreadyJumping,
+    #[error("link {0} does not connect to your current node")]
+    LinkNotAdjacent(LinkId),
+    #[error("not currently at a node")]
+    NotAtNode,
+    #[error("no path to the requested node")]
+    NoPath,
+    #[error("already at the requested node")]
+    AlreadyAtTarget,
+    #[error("unknown player")]
+    UnknownPlayer,
+    #[error("malformed request")]
+    MalformedRequest,
+    #[error("no room with that id")]
+    UnknownRoom,
+    #[error("not currently in a room")]
+    NotInRoom,
+}
+
+impl GameError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            GameError::UnknownLink(_) => "UnknownLink",
+            GameError::AlreadyJumping => "AlreadyJumping",
+            GameError::LinkNotAdjacent(_) => "LinkNotAdjacent",
+            GameError::NotAtNode => "NotAtNode",
+            GameError::NoPath => "NoPath",
+            GameError::AlreadyAtTarget => "AlreadyAtTarget",
+            GameError::UnknownPlayer => "UnknownPlayer",
+            GameError::MalformedRequest => "MalformedRequest",
+            GameError::UnknownRoom => "UnknownRoom",
+            GameError::NotInRoom => "NotInRoom",
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum Address {
     None,
@@ -30,6 +105,7 @@ pub enum Address {
 
 #[derive(Clone, Debug)]
 pub struct AddressResponse {
+    pub room: Option<RoomId>,
     pub whom: Address,
     pub response: Response,
 }
@@ -48,9 +124,14 @@ pub enum Request {
     NewPlayer,
     PlayerExit,
     GetState,
-    Restart,
-    Calc,
+    Restart {seed: Option<u64>},
     Jump {link_id: LinkId},
+    Navigate {target: NodeId},
+    CreateRoom,
+    ListRooms,
+    JoinRoom {room_id: RoomId},
+    LeaveRoom,
+    Query {room_id: RoomId},
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -107,27 +188,151 @@ enum Host {
 }
 
 #[derive(Serialize, Deserialize)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct Flid {
     id: PlayerId,
     host: Host,
+    // Remaining link ids queued by a Navigate request; consumed one hop at a
+    // time as the flid arrives at each intermediate node.
+    path: Vec<LinkId>,
 }
 
 #[derive(Serialize, Deserialize)]
 #[derive(Clone, Debug)]
 pub struct Game {
+    pub seed: u64,
     pub nodes: Vec<Node>,
     pub links: Vec<Link>,
     pub flids: Vec<Flid>,
+    // Rebuilt from `nodes` in `new`/`renew`; never sent to clients.
+    #[serde(skip)]
+    grid: SpatialGrid,
+}
+
+// Uniform spatial hash over node positions, bucketed into MIN_SPACING-sided
+// cells so nearest-neighbor and radius queries only scan the query cell and
+// an expanding ring of neighbors instead of sorting every node in the map.
+#[derive(Clone, Debug, Default)]
+struct SpatialGrid {
+    cells: HashMap<(i64, i64), Vec<Node>>,
+}
+
+impl SpatialGrid {
+    fn cell_of(pos: Point) -> (i64, i64) {
+        ((pos.x as f32 / MIN_SPACING).floor() as i64,
+         (pos.y as f32 / MIN_SPACING).floor() as i64)
+    }
+
+    fn insert(&mut self, node: Node) {
+        self.cells.entry(Self::cell_of(node.pos)).or_insert_with(Vec::new).push(node);
+    }
+
+    fn build(nodes: &[Node]) -> SpatialGrid {
+        let mut grid = SpatialGrid::default();
+        for &node in nodes {
+            grid.insert(node);
+        }
+        grid
+    }
+
+    // Returns up to `n` nodes within `dist` of `pos` (every node if `dist`
+    // is 0), nearest first. Expands outward ring by ring from the query
+    // cell so the scan touches only as many cells as needed to satisfy it.
+    fn query(&self, pos: Point, n: usize, dist: f32) -> Vec<Node> {
+        let n = if n == 0 { std::usize::MAX } else { n };
+        let (cx, cy) = Self::cell_of(pos);
+        let max_ring = if dist > 0. {
+            (dist / MIN_SPACING).ceil() as i64 + 1
+        } else {
+            self.cells.keys()
+                .map(|&(x, y)| (x - cx).abs().max((y - cy).abs()))
+                .max()
+                .unwrap_or(0)
+        };
+
+        let mut found = vec!();
+        let mut ring: i64 = 0;
+        loop {
+            for dx in -ring..=ring {
+                for dy in -ring..=ring {
+                    if ring > 0 && dx.abs() != ring && dy.abs() != ring {
+                        continue;
+                    }
+                    if let Some(nodes) = self.cells.get(&(cx + dx, cy + dy)) {
+                        for node in nodes {
+                            if dist == 0. || pos.dist(node.pos) < dist {
+                                found.push(*node);
+                            }
+                        }
+                    }
+                }
+            }
+            // Cells only bound Chebyshev distance, not Euclidean distance, so
+            // a node in a farther ring can still be closer than one already
+            // collected: regardless of whether `dist` bounds the search, we
+            // can only stop once the next ring's guaranteed minimum distance
+            // (ring * MIN_SPACING) beats our current worst-of-n candidate,
+            // not merely once we've collected n of them.
+            let enough = found.len() >= n && {
+                let mut distances: Vec<f32> = found.iter().map(|node| pos.dist(node.pos)).collect();
+                distances.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                let guaranteed_min_next_ring = ring as f32 * MIN_SPACING;
+                distances[n.min(distances.len()) - 1] <= guaranteed_min_next_ring
+            };
+
+            if enough || ring >= max_ring {
+                break;
+            }
+            ring += 1;
+        }
+
+        found.sort_by(|a, b| pos.dist(a.pos).partial_cmp(&pos.dist(b.pos)).unwrap());
+        found.truncate(n);
+        found
+    }
+}
+
+#[derive(Serialize)]
+#[derive(Clone, Debug)]
+pub struct RoomInfo {
+    pub id: RoomId,
+    pub player_count: usize,
+    pub in_progress: bool,
+}
+
+// Wraps an f64 edge cost so it can sit in a BinaryHeap; costs here are always
+// finite (derived from positive travel times and qualities), so the
+// partial_cmp unwrap never panics.
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+struct Cost(f64);
+
+impl Eq for Cost {}
+
+impl Ord for Cost {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.partial_cmp(other).unwrap()
+    }
 }
 
 fn noop() -> AddressResponse {
     AddressResponse {
+        room: None,
         whom: Address::None,
         response: Response::Nop
     }
 }
 
+fn error_to(room_id: RoomId, id: PlayerId, err: GameError) -> AddressResponse {
+    AddressResponse {
+        room: Some(room_id),
+        whom: Address::Player(id),
+        response: Response::Error {
+            code: err.code().to_string(),
+            message: err.to_string(),
+        },
+    }
+}
+
 impl Point {
     fn dist(self, other: Point) -> f32 {
         (((self.x - other.x).pow(2) + (self.y - other.y).pow(2)) as f32).sqrt()
@@ -165,20 +370,27 @@ impl Link {
 }
 
 impl Game {
-    pub fn new() -> Game {
-        let nodes = gen_nodes(100);
-        let links = gen_links(&nodes);
-        Game {nodes, links, flids: vec!()}
+    pub fn new(seed: u64) -> Game {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let nodes = gen_nodes(100, &mut rng);
+        let grid = SpatialGrid::build(&nodes);
+        let links = gen_links(&nodes, &grid, &mut rng);
+        Game {seed, nodes, links, flids: vec!(), grid}
     }
 
-    fn renew(&mut self) {
-        self.nodes = gen_nodes(100);
-        self.links = gen_links(&self.nodes);
+    fn renew(&mut self, seed: u64) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        self.seed = seed;
+        self.nodes = gen_nodes(100, &mut rng);
+        self.grid = SpatialGrid::build(&self.nodes);
+        self.links = gen_links(&self.nodes, &self.grid, &mut rng);
         //todo respawn players
         self.flids = vec!();
     }
 
-    fn calc(&mut self, _old_time: f64) -> f64 {
+    // Advances every flid in flight and returns the ones that just arrived at
+    // a node, so the caller only broadcasts when something actually changed.
+    fn calc(&mut self, _old_time: f64) -> (f64, Vec<Flid>) {
         let new_time = precise_time_s();
         //let dtime = new_time - old_time;
 
@@ -191,6 +403,7 @@ impl Game {
             links.insert(l.id, l);
         }
 
+        let mut just_arrived = vec!();
         for f in &mut self.flids {
             match f.host {
                 Host::Link(jump) => {
@@ -202,27 +415,112 @@ impl Game {
 
                     if jump.arrive_at <= new_time {
                         f.host = Host::Node(to.id);
+                        just_arrived.push(f.id);
                     }
                 },
                 Host::Node(_) => continue,
             }
         }
-        new_time
+
+        // A flid that arrived with a queued Navigate path immediately takes
+        // its next hop, so this has to run after the borrow above is done.
+        let mut arrived = vec!();
+        for id in just_arrived {
+            self.continue_path(id, new_time);
+            arrived.push(self.flids.iter().find(|f| f.id == id).unwrap().clone());
+        }
+        (new_time, arrived)
+    }
+
+    // Pops the next link off a flid's queued path (if any) and starts the jump.
+    fn continue_path(&mut self, id: PlayerId, time: f64) {
+        let next_link_id = {
+            let flid = self.flids.iter_mut().find(|f| f.id == id).unwrap();
+            if flid.path.is_empty() {
+                None
+            } else {
+                Some(flid.path.remove(0))
+            }
+        };
+
+        let link = match next_link_id {
+            None => return,
+            Some(link_id) => self.links.iter().find(|l| l.id == link_id).cloned(),
+        };
+
+        if let Some(link) = link {
+            let host = self.flids.iter().find(|f| f.id == id).unwrap().host;
+            if let Ok(jump) = self.jump(&host, &link, time) {
+                let flid = self.flids.iter_mut().find(|f| f.id == id).unwrap();
+                flid.host = Host::Link(jump);
+            }
+        }
     }
 
-    fn jump(&self, host: &Host, link: &Link, time: f64) -> Option<Jump> {
+    // Dijkstra over the link graph, weighting each edge by travel time divided
+    // by link quality so low-quality links cost more to cross.
+    fn shortest_path(&self, from: NodeId, to: NodeId) -> Option<Vec<LinkId>> {
+        let mut adj: HashMap<NodeId, Vec<(NodeId, LinkId, f64)>> = HashMap::new();
+        for link in &self.links {
+            let cost = self.time(link) / link.quality as f64;
+            adj.entry(link.n1).or_insert_with(Vec::new).push((link.n2, link.id, cost));
+            adj.entry(link.n2).or_insert_with(Vec::new).push((link.n1, link.id, cost));
+        }
+
+        let mut dist: HashMap<NodeId, f64> = HashMap::new();
+        let mut prev: HashMap<NodeId, LinkId> = HashMap::new();
+        let mut heap = BinaryHeap::new();
+
+        dist.insert(from, 0.);
+        heap.push(Reverse((Cost(0.), from)));
+
+        while let Some(Reverse((Cost(cost), node))) = heap.pop() {
+            if node == to {
+                break;
+            }
+            if cost > *dist.get(&node).unwrap_or(&std::f64::INFINITY) {
+                continue;
+            }
+            if let Some(neighbors) = adj.get(&node) {
+                for &(next, link_id, weight) in neighbors {
+                    let next_cost = cost + weight;
+                    if next_cost < *dist.get(&next).unwrap_or(&std::f64::INFINITY) {
+                        dist.insert(next, next_cost);
+                        prev.insert(next, link_id);
+                        heap.push(Reverse((Cost(next_cost), next)));
+                    }
+                }
+            }
+        }
+
+        if !dist.contains_key(&to) {
+            return None;
+        }
+
+        let mut link_ids = vec!();
+        let mut current = to;
+        while current != from {
+            let link_id = *prev.get(&current)?;
+            let link = self.links.iter().find(|l| l.id == link_id).unwrap();
+            current = if link.n1 == current { link.n2 } else { link.n1 };
+            link_ids.push(link_id);
+        }
+        link_ids.reverse();
+        Some(link_ids)
+    }
+
+    fn jump(&self, host: &Host, link: &Link, time: f64) -> Result<Jump, GameError> {
         match host {
-            Host::Link(_) => None,
+            Host::Link(_) => Err(GameError::AlreadyJumping),
             Host::Node(node_id) => {
-                if let Some(dir) = link.dir_from(&node_id) {
-                    Some(Jump {
+                match link.dir_from(&node_id) {
+                    Some(dir) => Ok(Jump {
                         id: link.id,
                         dir,
                         start_at: time,
                         arrive_at: time + self.time(link),
-                    })
-                } else {
-                    None
+                    }),
+                    None => Err(GameError::LinkNotAdjacent(link.id)),
                 }
             },
         }
@@ -235,138 +533,187 @@ impl Game {
     }
 
     pub fn main_loop(mut self,
+                     room_id: RoomId,
                      incoming: Receiver<PersonalRequest>,
                      outgoing: Sender<AddressResponse>) {
         let mut t = precise_time_s();
+        let started_at = t;
+        let ticker = tick(TICK);
         loop {
-            let p_req = incoming.recv().unwrap();
-            let id = p_req.player;
-            debug!("Game request: {:?}", p_req);
-
-            let resp = match p_req.request {
-                Request::NewPlayer => {
-                    let node = self.nodes[thread_rng().gen_range(0, self.nodes.len())];
-                    let flid = Flid {
-                        id,
-                        host: Host::Node(node.id),
-                    };
-                    self.flids.push(flid);
-
-                    outgoing.send(AddressResponse {
-                        whom: Address::Player(id),
-                        response: Response::Hello{id},
-                    }).unwrap();
-
-                    AddressResponse {
-                        whom: Address::All,
-                        response: Response::GameState(self.clone())
-                    }
-                }
-                Request::PlayerExit => {
-                    self.flids.retain(|f| f.id != id);
-
-                    AddressResponse {
-                        whom: Address::All,
-                        response: Response::GameState(self.clone())
+            select! {
+                recv(ticker) -> _ => {
+                    let (new_t, arrived) = self.calc(t);
+                    t = new_t;
+                    if !arrived.is_empty() {
+                        outgoing.send(AddressResponse {
+                            room: Some(room_id),
+                            whom: Address::All,
+                            response: Response::FlidState { flids: arrived },
+                        }).unwrap();
                     }
                 },
-                Request::GetState => {
-                    t = self.calc(t);
-                    AddressResponse {
-                        whom: Address::Player(id),
-                        response: Response::GameState(self.clone())
-                    }
-                }
-                Request::Restart => {
-                    self.renew();
-                    t = precise_time_s();
-                    AddressResponse {
-                        whom: Address::All,
-                        response: Response::GameState(self.clone())
-                    }
-                }
-                Request::Calc => {
-                    if precise_time_s() - t < 0.2 {
-                        noop()
-                    } else {
-                        t = self.calc(t);
-                        AddressResponse {
-                            whom: Address::All,
-                            response: Response::FlidState { flids: self.flids.clone() }
+                recv(incoming) -> p_req => {
+                    // The dispatcher drops this room's sender once it's
+                    // empty, so a disconnected-and-empty channel here means
+                    // "room torn down" rather than a bug: exit the thread.
+                    let p_req = match p_req {
+                        Ok(p_req) => p_req,
+                        Err(_) => {
+                            debug!("Room {} has no more players; shutting down", room_id);
+                            break;
                         }
-                    }
-                }
-                Request::Jump {link_id} => {
-                    let flid = self.flids.iter().find(|f| f.id == id).unwrap();
-                    let link = self.links.iter().find(|l| l.id == link_id);
-                    match link {
-                        None => noop(),
-                        Some(l) => match self.jump(&flid.host, l, t) {
-                            None => noop(),
-                            Some(jump) => {
-                                let flid = self.flids.iter_mut().find(|f| f.id == id).unwrap();
-                                flid.host = Host::Link(jump);
-                                AddressResponse {
-                                    whom: Address::All,
-                                    response: Response::FlidUpdate{flid: flid.clone()},
-                                }
-                            },
+                    };
+                    let id = p_req.player;
+                    debug!("Game request for room {}: {:?}", room_id, p_req);
+
+                    let resp = match p_req.request {
+                        Request::NewPlayer => {
+                            let node = self.nodes[thread_rng().gen_range(0, self.nodes.len())];
+                            let flid = Flid {
+                                id,
+                                host: Host::Node(node.id),
+                                path: vec!(),
+                            };
+                            self.flids.push(flid);
+
+                            outgoing.send(AddressResponse {
+                                room: Some(room_id),
+                                whom: Address::Player(id),
+                                response: Response::Hello{id},
+                            }).unwrap();
+
+                            AddressResponse {
+                                room: Some(room_id),
+                                whom: Address::All,
+                                response: Response::GameState(self.clone())
+                            }
+                        }
+                        Request::PlayerExit => {
+                            self.flids.retain(|f| f.id != id);
+
+                            AddressResponse {
+                                room: Some(room_id),
+                                whom: Address::All,
+                                response: Response::GameState(self.clone())
+                            }
                         },
-                    }
+                        Request::GetState => {
+                            AddressResponse {
+                                room: Some(room_id),
+                                whom: Address::Player(id),
+                                response: Response::GameState(self.clone())
+                            }
+                        }
+                        Request::Restart {seed} => {
+                            let seed = seed.unwrap_or_else(|| thread_rng().gen());
+                            self.renew(seed);
+                            t = precise_time_s();
+                            AddressResponse {
+                                room: Some(room_id),
+                                whom: Address::All,
+                                response: Response::GameState(self.clone())
+                            }
+                        }
+                        Request::Jump {link_id} => {
+                            match self.flids.iter().find(|f| f.id == id) {
+                                None => error_to(room_id, id, GameError::UnknownPlayer),
+                                Some(flid) => {
+                                    let host = flid.host;
+                                    match self.links.iter().find(|l| l.id == link_id) {
+                                        None => error_to(room_id, id, GameError::UnknownLink(link_id)),
+                                        Some(l) => match self.jump(&host, l, t) {
+                                            Err(e) => error_to(room_id, id, e),
+                                            Ok(jump) => {
+                                                let flid = self.flids.iter_mut().find(|f| f.id == id).unwrap();
+                                                flid.host = Host::Link(jump);
+                                                AddressResponse {
+                                                    room: Some(room_id),
+                                                    whom: Address::All,
+                                                    response: Response::FlidUpdate{flid: flid.clone()},
+                                                }
+                                            },
+                                        },
+                                    }
+                                }
+                            }
+                        }
+                        Request::Navigate {target} => {
+                            let current = self.flids.iter().find(|f| f.id == id).map(|f| f.host);
+                            match current {
+                                None => error_to(room_id, id, GameError::UnknownPlayer),
+                                Some(Host::Link(_)) => error_to(room_id, id, GameError::NotAtNode),
+                                Some(Host::Node(current_node)) if current_node == target => error_to(room_id, id, GameError::AlreadyAtTarget),
+                                Some(Host::Node(current_node)) => {
+                                    match self.shortest_path(current_node, target) {
+                                        None => error_to(room_id, id, GameError::NoPath),
+                                        Some(link_ids) => {
+                                            let flid = self.flids.iter_mut().find(|f| f.id == id).unwrap();
+                                            flid.path = link_ids.clone();
+                                            self.continue_path(id, t);
+                                            AddressResponse {
+                                                room: Some(room_id),
+                                                whom: Address::Player(id),
+                                                response: Response::Route{link_ids},
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        Request::Query {room_id: _} => {
+                            AddressResponse {
+                                room: Some(room_id),
+                                whom: Address::Player(id),
+                                response: Response::ServerStatus {
+                                    status: ServerStatus::Ok {
+                                        player_count: self.flids.len(),
+                                        node_count: self.nodes.len(),
+                                        link_count: self.links.len(),
+                                        seed: self.seed,
+                                        uptime: t - started_at,
+                                    },
+                                },
+                            }
+                        }
+                        Request::CreateRoom | Request::ListRooms
+                        | Request::JoinRoom{..} | Request::LeaveRoom => {
+                            debug!("Room {} received a lobby-level request that should have been \
+                                    handled by the dispatcher: {:?}", room_id, p_req.request);
+                            noop()
+                        }
+                    };
+                    debug!("Game response: {:?}", resp);
+                    outgoing.send(resp).unwrap();
                 }
-            };
-            debug!("Game response: {:?}", resp);
-            outgoing.send(resp).unwrap();
-        }
-    }
-}
-
-fn get_nearest_nodes(pos: &Point, nodes: &[Node], n: usize, dist: f32) -> Vec<Node> {
-    let mut n = n;
-
-    if n == 0 {
-        n = nodes.len()
-    }
-
-    let mut source = nodes.to_vec();
-    source.sort_by(|a, b| pos.dist(a.pos).partial_cmp(&pos.dist(b.pos)).unwrap());
-
-    let mut res = vec!();
-    for node in &source {
-        if dist == 0. || pos.dist(node.pos) < dist {
-            res.push(node.clone())
-        }
-        if res.len() >= n {
-            break
+            }
         }
     }
-    res
 }
 
-fn gen_nodes(n: usize) -> Vec<Node> {
+fn gen_nodes(n: usize, rng: &mut StdRng) -> Vec<Node> {
     let mut res = vec!();
-    let mut rng = thread_rng();
+    let mut grid = SpatialGrid::default();
 
     while res.len() < n {
         let x = rng.gen_range(-1000, 1000);
         let y = rng.gen_range(-1000, 1000);
         let pos = Point{x, y};
-        if get_nearest_nodes(&pos, &res, 1, 100f32).len() > 0 {
+        if !grid.query(pos, 1, MIN_SPACING).is_empty() {
             continue;
         }
 
         let node = Node{id: res.len(), pos, size: rng.gen_range(0.5, 1.5)};
+        grid.insert(node);
         res.push(node)
     }
     res
 }
 
-fn gen_links(nodes: &[Node]) -> Vec<Link> {
-    let mut rng = thread_rng();
+fn gen_links(nodes: &[Node], grid: &SpatialGrid, rng: &mut StdRng) -> Vec<Link> {
     let mut res: Vec<Link> = vec!();
     for &node in nodes {
         let links_count = rng.gen_range(2, 5) + 1;
-        let nearest = get_nearest_nodes(&node.pos, nodes, links_count, 0.)[1..].to_vec();
+        let nearest = grid.query(node.pos, links_count, 0.)[1..].to_vec();
         for n in &nearest {
             if let None = res.iter().find(|l| l.between_ids(&node.id, &n.id)) {
                 let id = res.len();
@@ -1,48 +1,80 @@
 mod game;
+mod crypto;
 
 use std::env;
 use std::thread;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use crossbeam_channel::{select, unbounded, Sender, Receiver};
+use rand::{thread_rng, Rng};
 use ws::{listen, Handler, CloseCode};
 use serde_json::{to_string, from_str};
 use log::{debug};
 
-use crate::game::{Game, Response, Address, AddressResponse, Request, PersonalRequest, PlayerId};
+use crate::game::{Game, Response, Address, AddressResponse, Request, PersonalRequest, PlayerId, RoomId, RoomInfo, ServerStatus, GameError};
+use crate::crypto::SessionKey;
 
 enum ServerEvent {
-    NewPlayer {id: PlayerId, ws: ws::Sender},
+    NewPlayer {id: PlayerId, ws: ws::Sender, session_key: Option<SessionKey>},
     PlayerExit {id: PlayerId},
+    PlayerRequest {request: PersonalRequest},
 }
 
 struct PlayerHandler {
     id: PlayerId,
     ws: ws::Sender,
-    to_game: Sender<PersonalRequest>,
     to_dispatcher: Sender<ServerEvent>,
+    encrypt: bool,
+    session_key: Option<SessionKey>,
 }
 
 impl Handler for PlayerHandler {
     fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
         debug!("Player {} open connection", self.id);
-        self.to_dispatcher.send(ServerEvent::NewPlayer {id: self.id, ws: self.ws.clone()}).unwrap();
+
+        // The handshake: hand the client its session key as a plain
+        // binary frame before anything else crosses the wire encrypted.
+        // This key frame is itself in the clear (see crypto.rs), so this
+        // is payload obfuscation, not confidentiality against wire
+        // observation.
+        self.session_key = if self.encrypt {
+            let key = crypto::generate_session_key();
+            self.ws.send(ws::Message::Binary(key.to_vec()))?;
+            Some(key)
+        } else {
+            None
+        };
+
+        self.to_dispatcher.send(ServerEvent::NewPlayer {
+            id: self.id,
+            ws: self.ws.clone(),
+            session_key: self.session_key,
+        }).unwrap();
         Ok(())
     }
 
     fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
         debug!("Player {} send message: {}", self.id, msg);
 
-        if msg.is_text() {
-            match from_str(msg.as_text().unwrap()) {
-                Ok(r) => {
-                    self.to_game.send(personal(self.id, r)).unwrap();
-                }
-                Err(_) => {
-                    debug!("Player {} sent wrong message!", self.id)
-                }
+        let text = match &self.session_key {
+            Some(key) => crypto::decrypt(key, &msg.into_data()).and_then(|bytes| String::from_utf8(bytes).ok()),
+            None if msg.is_text() => Some(msg.as_text().unwrap().to_string()),
+            None => None,
+        };
+
+        match text.and_then(|t| from_str::<Request>(&t).ok()) {
+            Some(r) => {
+                self.to_dispatcher.send(ServerEvent::PlayerRequest {
+                    request: personal(self.id, r),
+                }).unwrap();
+            }
+            None => {
+                debug!("Player {} sent wrong message!", self.id);
+                let err = GameError::MalformedRequest;
+                send_to(Some(&self.ws), self.session_key.as_ref(), &Response::Error {
+                    code: err.code().to_string(),
+                    message: err.to_string(),
+                });
             }
-        } else {
-            debug!("Player {} sent non-text message!", self.id)
         }
 
         return Ok(())
@@ -58,32 +90,69 @@ impl Handler for PlayerHandler {
     }
 }
 
-fn send_to(ws: Option<&ws::Sender>, response: &Response) -> bool {
+fn send_to(ws: Option<&ws::Sender>, session_key: Option<&SessionKey>, response: &Response) -> bool {
     match ws {
         None => false,
-        Some(ws) => match ws.send(ws::Message::from(to_string(response).unwrap())) {
-            Err(_) => false,
-            Ok(_) => true,
+        Some(ws) => {
+            let payload = to_string(response).unwrap();
+            let message = match session_key {
+                Some(key) => ws::Message::Binary(crypto::encrypt(key, payload.as_bytes())),
+                None => ws::Message::from(payload),
+            };
+            match ws.send(message) {
+                Err(_) => false,
+                Ok(_) => true,
+            }
         }
     }
+}
 
+// Reports a dispatcher-level `GameError` (one not tied to any particular
+// room's game loop) straight back to the player who caused it.
+fn send_error(to_game_responses: &Sender<AddressResponse>, id: PlayerId, err: GameError) {
+    to_game_responses.send(AddressResponse {
+        room: None,
+        whom: Address::Player(id),
+        response: Response::Error {
+            code: err.code().to_string(),
+            message: err.to_string(),
+        },
+    }).unwrap();
+}
+
+// A room's simulation runs on its own thread; the dispatcher only keeps the
+// handle needed to route requests into it and to know who is currently in it.
+struct RoomHandle {
+    to_game: Sender<PersonalRequest>,
+    players: HashSet<PlayerId>,
 }
 
-fn send(list: &HashMap<PlayerId, ws::Sender>, addr: &Address, response: &Response) {
+fn send(list: &HashMap<PlayerId, ws::Sender>, keys: &HashMap<PlayerId, SessionKey>,
+        scope: Option<&HashSet<PlayerId>>, addr: &Address, response: &Response) {
     debug!("Send response to {:?}: {:?}", addr, response);
     match *addr {
         Address::None => debug!("Some answer to no one"),
         Address::Player(ref id) => {
-            send_to(list.get(id), response);
+            send_to(list.get(id), keys.get(id), response);
         }
         /*Address::SomePlayers(ref ids) => {
             for id in ids {
-                send_to(list.get(id), response);
+                send_to(list.get(id), keys.get(id), response);
             }
         }*/
         Address::All => {
-            for ref ws in list.values() {
-                send_to(Some(ws), response);
+            // `Address::All` always means "everyone in the sender's room",
+            // never the whole server; if the room's already gone (e.g. it
+            // was torn down right after this message was queued) there's no
+            // one left to resolve that scope to, so drop the message rather
+            // than falling back to a global broadcast.
+            match scope {
+                Some(members) => {
+                    for id in members {
+                        send_to(list.get(id), keys.get(id), response);
+                    }
+                }
+                None => debug!("No scope to resolve Address::All against, dropping"),
             }
         }
     };
@@ -93,31 +162,163 @@ fn personal(id: PlayerId, r: Request) -> PersonalRequest {
     PersonalRequest{player: id, request: r}
 }
 
+fn spawn_room(room_id: RoomId, rooms: &mut HashMap<RoomId, RoomHandle>, to_game_responses: Sender<AddressResponse>) {
+    let (to_game, from_players) = unbounded();
+    let game = Game::new(thread_rng().gen());
+
+    thread::spawn(move || {
+        game.main_loop(room_id, from_players, to_game_responses);
+    });
+
+    rooms.insert(room_id, RoomHandle {
+        to_game,
+        players: HashSet::new(),
+    });
+}
+
+fn join_room(id: PlayerId, room_id: RoomId,
+             rooms: &mut HashMap<RoomId, RoomHandle>,
+             player_room: &mut HashMap<PlayerId, RoomId>) {
+    if let Some(room) = rooms.get_mut(&room_id) {
+        room.players.insert(id);
+        player_room.insert(id, room_id);
+        room.to_game.send(personal(id, Request::NewPlayer)).unwrap();
+    }
+}
+
+fn leave_current_room(id: PlayerId,
+                       rooms: &mut HashMap<RoomId, RoomHandle>,
+                       player_room: &mut HashMap<PlayerId, RoomId>) {
+    if let Some(room_id) = player_room.remove(&id) {
+        let now_empty = if let Some(room) = rooms.get_mut(&room_id) {
+            room.players.remove(&id);
+            room.to_game.send(personal(id, Request::PlayerExit)).unwrap();
+            room.players.is_empty()
+        } else {
+            false
+        };
+
+        // Dropping the RoomHandle drops its `to_game` sender, which
+        // disconnects the room's channel; the room's game loop notices on
+        // its next `select!` iteration and exits, ending its thread and
+        // its 50ms tick timer instead of leaking them forever.
+        if now_empty {
+            debug!("Room {} is empty, tearing it down", room_id);
+            rooms.remove(&room_id);
+        }
+    }
+}
+
+fn handle_player_request(req: PersonalRequest,
+                          rooms: &mut HashMap<RoomId, RoomHandle>,
+                          player_room: &mut HashMap<PlayerId, RoomId>,
+                          to_game_responses: &Sender<AddressResponse>,
+                          next_room_id: &mut RoomId) {
+    let id = req.player;
+    match req.request {
+        Request::CreateRoom => {
+            let room_id = *next_room_id;
+            *next_room_id += 1;
+            spawn_room(room_id, rooms, to_game_responses.clone());
+            join_room(id, room_id, rooms, player_room);
+        }
+        Request::ListRooms => {
+            let room_list = rooms.iter().map(|(&room_id, room)| RoomInfo {
+                id: room_id,
+                player_count: room.players.len(),
+                in_progress: !room.players.is_empty(),
+            }).collect();
+
+            to_game_responses.send(AddressResponse {
+                room: None,
+                whom: Address::Player(id),
+                response: Response::RoomList{rooms: room_list},
+            }).unwrap();
+        }
+        Request::JoinRoom{room_id} => {
+            if rooms.contains_key(&room_id) {
+                leave_current_room(id, rooms, player_room);
+                join_room(id, room_id, rooms, player_room);
+            } else {
+                debug!("Player {} tried to join unknown room {}", id, room_id);
+                send_error(to_game_responses, id, GameError::UnknownRoom);
+            }
+        }
+        Request::LeaveRoom => {
+            leave_current_room(id, rooms, player_room);
+        }
+        Request::Query {room_id} => {
+            match rooms.get(&room_id) {
+                Some(room) => room.to_game.send(req).unwrap(),
+                None => to_game_responses.send(AddressResponse {
+                    room: None,
+                    whom: Address::Player(id),
+                    response: Response::ServerStatus {
+                        status: ServerStatus::Error {
+                            message: format!("unknown room {}", room_id),
+                        },
+                    },
+                }).unwrap(),
+            }
+        }
+        _ => {
+            match player_room.get(&id) {
+                Some(room_id) => {
+                    if let Some(room) = rooms.get(room_id) {
+                        room.to_game.send(req).unwrap();
+                    }
+                }
+                None => {
+                    debug!("Player {} is not in a room, dropping {:?}", id, req.request);
+                    send_error(to_game_responses, id, GameError::NotInRoom);
+                }
+            }
+        }
+    }
+}
+
 fn dispatch(
     from_server: Receiver<ServerEvent>,
     from_game: Receiver<AddressResponse>,
-    to_game: Sender<PersonalRequest>)
+    to_game_responses: Sender<AddressResponse>)
 {
     let mut to_players: HashMap<PlayerId, ws::Sender> = HashMap::new();
+    let mut player_keys: HashMap<PlayerId, SessionKey> = HashMap::new();
+    let mut rooms: HashMap<RoomId, RoomHandle> = HashMap::new();
+    let mut player_room: HashMap<PlayerId, RoomId> = HashMap::new();
+    let mut next_room_id: RoomId = 0;
+
     loop {
         select! {
             recv(from_server) -> server_event => {
                 let event = server_event.unwrap();
                 match event {
-                    ServerEvent::NewPlayer{id, ws} => {
+                    ServerEvent::NewPlayer{id, ws, session_key} => {
                         debug!("Added player {} to dispatcher", id);
                         to_players.insert(id, ws);
-                        to_game.send(personal(id, Request::NewPlayer)).unwrap();
+                        if let Some(key) = session_key {
+                            player_keys.insert(id, key);
+                        }
                     }
                     ServerEvent::PlayerExit{id} => {
                         debug!("Remove player {} from dispatcher", id);
                         to_players.remove(&id);
+                        player_keys.remove(&id);
+                        leave_current_room(id, &mut rooms, &mut player_room);
+                    }
+                    ServerEvent::PlayerRequest{request} => {
+                        handle_player_request(
+                            request, &mut rooms, &mut player_room,
+                            &to_game_responses, &mut next_room_id);
                     }
                 }
             },
             recv(from_game) -> game_response => {
                 let response = game_response.unwrap();
-                send(&to_players, &response.whom, &response.response);
+                let scope = response.room.as_ref()
+                    .and_then(|room_id| rooms.get(room_id))
+                    .map(|room| &room.players);
+                send(&to_players, &player_keys, scope, &response.whom, &response.response);
             }
         }
     }
@@ -146,20 +347,17 @@ fn main() {
     };
     addr = format!("0.0.0.0:{}", port);
 
-    let (to_game, from_players) = unbounded();
-    let (to_dispatcher, from_server) = unbounded();
-    let (to_dispatcher_game, from_game) = unbounded();
-
-    let to_game2 = to_game.clone();
+    // Plaintext JSON stays the default so the server is still debuggable
+    // straight from a browser; set ENCRYPT=1 to require the ChaCha20-
+    // Poly1305 handshake described in crypto.rs.
+    let encrypt = env::var("ENCRYPT").map(|val| val != "0").unwrap_or(false);
+    debug!("Payload encryption {}", if encrypt { "enabled" } else { "disabled" });
 
-    let g = Game::new();
-
-    thread::spawn(|| {
-        dispatch(from_server, from_game, to_game);
-    });
+    let (to_dispatcher, from_server) = unbounded();
+    let (to_game_responses, from_game) = unbounded();
 
-    thread::spawn(|| {
-        g.main_loop(from_players, to_dispatcher_game);
+    thread::spawn(move || {
+        dispatch(from_server, from_game, to_game_responses);
     });
 
     let mut last_id = 0;
@@ -170,8 +368,9 @@ fn main() {
         PlayerHandler {
             id: last_id,
             ws,
-            to_game: to_game2.clone(),
-            to_dispatcher: to_dispatcher.clone()
+            to_dispatcher: to_dispatcher.clone(),
+            encrypt,
+            session_key: None,
         }
     }).unwrap();
 }